@@ -1,6 +1,4 @@
 use std;
-use std::mem::size_of;
-use std::slice;
 use std::path::Path;
 
 use failure::{Error, ResultExt};
@@ -14,6 +12,42 @@ use binary_parser::{parse_binary, BinaryInfo};
 use utils::{copy_struct, copy_pointer};
 use python_interpreters::{InterpreterState, ThreadState};
 
+/// Width of pointers (and therefore of all pointer-sized struct fields) in the target
+/// process. Used today only to *detect* a target whose width differs from py-spy's own and
+/// reject it cleanly: the `InterpreterState`/`ThreadState` bindings in `python_bindings` are
+/// generated against the host's `usize`, so a mismatched-width target can't actually be
+/// unwound yet (see `PythonSpy::new`). Profiling a 32-bit target from a 64-bit py-spy (and
+/// vice versa) remains unimplemented; that needs `python_bindings` generated for both widths.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PointerWidth {
+    Bits32,
+    Bits64
+}
+
+impl PointerWidth {
+    pub fn bytes(&self) -> usize {
+        match self {
+            PointerWidth::Bits32 => 4,
+            PointerWidth::Bits64 => 8
+        }
+    }
+
+    /// The pointer width py-spy itself was built with, and therefore the width of the
+    /// `InterpreterState`/`ThreadState` struct layouts its `python_bindings` were generated for.
+    pub fn host() -> PointerWidth {
+        if cfg!(target_pointer_width = "64") { PointerWidth::Bits64 } else { PointerWidth::Bits32 }
+    }
+}
+
+impl std::fmt::Display for PointerWidth {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PointerWidth::Bits32 => write!(f, "32-bit"),
+            PointerWidth::Bits64 => write!(f, "64-bit")
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct PythonSpy {
     pub pid: u32,
@@ -23,7 +57,8 @@ pub struct PythonSpy {
     pub threadstate_address: usize,
     pub python_filename: String,
     pub python_install_path: String,
-    pub version_string: String
+    pub version_string: String,
+    pub pointer_width: PointerWidth
 }
 
 impl PythonSpy {
@@ -31,10 +66,23 @@ impl PythonSpy {
         let process = (pid as Pid).try_into_process_handle().context("Failed to open target process")?;
 
         // get basic process information (memory maps/symbols etc)
-        let python_info = PythonProcessInfo::new(pid)?;
+        let python_info = PythonProcessInfo::new(pid, process)?;
+
+        // NOTE: profiling a target whose pointer width differs from py-spy's own isn't
+        // supported yet -- the InterpreterState/ThreadState bindings we read interpreter
+        // structs with are generated against the host's usize, so a 32-bit target's structs
+        // would be misread by a 64-bit py-spy (and vice versa) if we let this through. Detect
+        // that case and bail out cleanly here rather than silently reading garbage once we
+        // get to interpreter/thread-state struct reads further down.
+        let pointer_width = pointer_width_of(&python_info.python_filename)?;
+        if pointer_width != PointerWidth::host() {
+            return Err(format_err!(
+                "Can't profile a {} process from a {} py-spy build: this isn't supported yet",
+                pointer_width, PointerWidth::host()));
+        }
 
         let version = get_python_version(&python_info, process)?;
-        let interpreter_address = get_interpreter_address(&python_info, process, &version)?;
+        let interpreter_address = get_interpreter_address(&python_info, process, &version, pointer_width)?;
 
         // lets us figure out which thread has the GIL
         let threadstate_address = match python_info.get_symbol("_PyThreadState_Current") {
@@ -42,8 +90,10 @@ impl PythonSpy {
             None => 0
         };
 
-        // Figure out the base path of the python install
-        let python_install_path = {
+        // Figure out the base path of the python install (i.e. sys.prefix). Prefer reading
+        // it directly out of the process, since guessing from the binary path breaks for
+        // virtualenvs, conda environments, and relocated installs.
+        let python_install_path = get_python_prefix(pid, &python_info, process).unwrap_or_else(|| {
             let mut python_path = Path::new(&python_info.python_filename);
             if let Some(parent) = python_path.parent() {
                 python_path = parent;
@@ -54,14 +104,15 @@ impl PythonSpy {
                 }
             }
             python_path.to_str().unwrap().to_string()
-        };
+        });
 
         let version_string = format!("python{}.{}", version.major, version.minor);
 
         Ok(PythonSpy{pid, process, version, interpreter_address, threadstate_address,
                      python_filename: python_info.python_filename,
                      python_install_path,
-                     version_string})
+                     version_string,
+                     pointer_width})
     }
 
     /// Creates a PythonSpy object, retrying up to max_retries times
@@ -94,20 +145,23 @@ impl PythonSpy {
 
     /// Gets a StackTrace for each thread in the current process
     pub fn get_stack_traces(&self) -> Result<Vec<StackTrace>, Error> {
-        match self.version {
-            // Currently 3.7.x and 3.8.0a0 have the same ABI, but this might change
-            // as 3.8 evolvess
-            Version{major: 3, minor: 8, ..} => self._get_stack_traces::<v3_7_0::_is>(),
-            Version{major: 3, minor: 7, ..} => self._get_stack_traces::<v3_7_0::_is>(),
-            Version{major: 3, minor: 6, ..} => self._get_stack_traces::<v3_6_6::_is>(),
-            // ABI for 3.4 and 3.5 is the same for our purposes
-            Version{major: 3, minor: 5, ..} => self._get_stack_traces::<v3_5_5::_is>(),
-            Version{major: 3, minor: 4, ..} => self._get_stack_traces::<v3_5_5::_is>(),
-            Version{major: 3, minor: 3, ..} => self._get_stack_traces::<v3_3_7::_is>(),
-            // ABI for 2.3/2.4/2.5/2.6/2.7 is also compatible
-            Version{major: 2, minor: 3...7, ..} => self._get_stack_traces::<v2_7_15::_is>(),
-            _ => Err(format_err!("Unsupported version of Python: {}", self.version)),
+        if self.version.implementation != InterpreterImplementation::CPython {
+            return Err(format_err!("{} unwinding not yet supported (detected {})",
+                                    self.version.implementation, self.version));
         }
+
+        // Try each known-compatible layout for this version in turn (there's normally just
+        // one), so that a newer minor release sharing its ABI with the latest known bindings
+        // keeps working instead of erroring outright.
+        let candidates = layout_candidates(&self.version);
+        let mut last_err = None;
+        for layout in &candidates {
+            match (layout.stack_traces)(self) {
+                Ok(traces) => return Ok(traces),
+                Err(err) => last_err = Some(err)
+            }
+        }
+        Err(last_err.unwrap_or_else(|| format_err!("Unsupported version of Python: {}", self.version)))
     }
 
     // implementation of get_stack_traces, where we have a type for the InterpreterState
@@ -160,6 +214,65 @@ impl PythonSpy {
         }
     }
 }
+/// Reads sys.prefix out of the process, used to correctly locate the stdlib/site-packages
+/// for virtualenvs, conda environments, and other installs that don't live next to the
+/// interpreter binary.
+///
+/// On macOS this resolves the real sys.prefix via symbols. On Linux we don't yet have a way
+/// to read it directly (that needs walking PyInterpreterState.modules -> the "sys" module's
+/// __dict__ -> "prefix", which needs PyDictObject/PyUnicodeObject bindings we don't generate
+/// here); as a partial stand-in we read VIRTUAL_ENV/CONDA_PREFIX out of the target's own
+/// environment, which only covers the activated-venv/conda case. A plain relocated or
+/// renamed install exec'd without activation (the common case for containers/services)
+/// still falls through to the binary-path heuristic in `PythonSpy::new`. Tracked as a follow-up to do the
+/// real module-dict chase on Linux.
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+fn get_python_prefix(pid: u32, python_info: &PythonProcessInfo, process: ProcessHandle) -> Option<String> {
+    // Py_GetPrefix/Py_GetExecPrefix return a pointer to the (immutable) prefix string, the
+    // same trick used by the Py_GetVersion.version lookup in get_python_version. This only works on macOS,
+    // where profile symbols happen to resolve to the data address they point to.
+    for symbol in &["Py_GetPrefix.prefix", "Py_GetExecPrefix.exec_prefix"] {
+        if let Some(&addr) = python_info.get_symbol(symbol) {
+            if let Ok(bytes) = copy_address(addr as usize, 512, &process) {
+                if let Some(prefix) = read_cstring(&bytes) {
+                    return Some(prefix);
+                }
+            }
+        }
+    }
+
+    // On Linux we can't yet chase sys.prefix through the module dict (see doc comment
+    // above), so fall back to VIRTUAL_ENV/CONDA_PREFIX: this only helps when the target was
+    // launched with an activated virtualenv/conda env, not a plain relocated install.
+    #[cfg(target_os = "linux")]
+    {
+        if let Some(prefix) = get_prefix_from_environ(pid) {
+            return Some(prefix);
+        }
+    }
+
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn get_prefix_from_environ(pid: u32) -> Option<String> {
+    let environ = std::fs::read(format!("/proc/{}/environ", pid)).ok()?;
+    environ.split(|&b| b == 0)
+        .find_map(|var| {
+            for key in &["VIRTUAL_ENV=", "CONDA_PREFIX="] {
+                if var.starts_with(key.as_bytes()) {
+                    return std::str::from_utf8(&var[key.len()..]).ok().map(|s| s.to_owned());
+                }
+            }
+            None
+        })
+}
+
+fn read_cstring(bytes: &[u8]) -> Option<String> {
+    let end = bytes.iter().position(|&b| b == 0)?;
+    std::str::from_utf8(&bytes[..end]).ok().map(|s| s.to_owned())
+}
+
 /// Returns the version of python running in the process.
 fn get_python_version(python_info: &PythonProcessInfo, process: ProcessHandle)
         -> Result<Version, Error> {
@@ -171,7 +284,7 @@ fn get_python_version(python_info: &PythonProcessInfo, process: ProcessHandle)
     // otherwise get version info from scanning BSS section for sys.version string
     let bss = copy_address(python_info.python_binary.bss_addr as usize,
                            python_info.python_binary.bss_size as usize, &process)?;
-    match Version::scan_bytes(&bss) {
+    let version = match Version::scan_bytes(&bss) {
         Ok(version) => Ok(version),
         Err(err) => {
             match python_info.libpython_binary {
@@ -184,38 +297,57 @@ fn get_python_version(python_info: &PythonProcessInfo, process: ProcessHandle)
                 None => Err(err)
             }
         }
-    }
+    }?;
+
+    // The exported symbols are a more reliable implementation signal than the banner scan
+    // above, but only when the banner scan itself couldn't tell: a confident non-CPython
+    // match from the banner (e.g. the PyPy banner) should never be downgraded back to
+    // CPython just because the expected non-CPython symbols weren't found (stripped
+    // binaries, different builds, etc).
+    let implementation = match version.implementation {
+        InterpreterImplementation::CPython => python_info.likely_implementation(),
+        other => other
+    };
+    Ok(Version{implementation, ..version})
 }
 
 fn get_interpreter_address(python_info: &PythonProcessInfo,
                            process: ProcessHandle,
-                           version: &Version) -> Result<usize, Error> {
+                           version: &Version,
+                           pointer_width: PointerWidth) -> Result<usize, Error> {
+    // We only know the CPython InterpreterState/ThreadState layouts right now, so bail out
+    // early with a precise error rather than scanning memory with the wrong struct layout.
+    if version.implementation != InterpreterImplementation::CPython {
+        return Err(format_err!("{} unwinding not yet supported (detected {})",
+                                version.implementation, version));
+    }
+
+    let candidates = layout_candidates(version);
+    if candidates.is_empty() {
+        return Err(format_err!("Unsupported version of Python: {}", version));
+    }
+
     // get the address of the main PyInterpreterState object from loaded symbols if we can
-    // (this tends to be faster than scanning through the bss section)
-    match version {
-        Version{major: 3, minor: 7, ..} => {
-            if let Some(&addr) = python_info.get_symbol("_PyRuntime") {
-                // TODO: we actually want _PyRuntime.interpeters.head, and probably should
-                // generate bindings for the pyruntime object rather than hardcode the offset (24) here
-                return Ok(copy_struct((addr + 24) as usize, &process)?);
-            }
-        },
-        _ => {
-            if let Some(&addr) = python_info.get_symbol("interp_head") {
-                return Ok(copy_struct(addr as usize, &process)
-                    .context("Failed to copy PyInterpreterState location from process")?);
+    // (this tends to be faster than scanning through the bss section). Try every candidate
+    // layout in turn using its own matching symbol lookup, verifying each address against
+    // that same layout before trusting it, rather than only ever checking the first
+    // candidate's verifier against a single hardcoded symbol lookup.
+    for layout in &candidates {
+        if let Some(addr) = (layout.symbol_addr)(python_info, process) {
+            if (layout.verify)(addr, &python_info.maps, process) {
+                return Ok(addr);
             }
         }
-    };
+    }
 
     // try scanning the BSS section of the binary for things that might be the interpreterstate
-    match get_interpreter_address_from_binary(&python_info.python_binary, &python_info.maps, process, version) {
+    match get_interpreter_address_from_binary(&python_info.python_binary, &python_info.maps, process, version, pointer_width) {
         Ok(addr) => Ok(addr),
         // Before giving up, try again if there is a libpython.so
         Err(err) => {
             match python_info.libpython_binary {
                 Some(ref libpython) => {
-                    Ok(get_interpreter_address_from_binary(libpython, &python_info.maps, process, version)?)
+                    Ok(get_interpreter_address_from_binary(libpython, &python_info.maps, process, version, pointer_width)?)
                 },
                 None => Err(err)
             }
@@ -226,56 +358,252 @@ fn get_interpreter_address(python_info: &PythonProcessInfo,
 fn get_interpreter_address_from_binary(binary: &BinaryInfo,
                                        maps: &[MapRange],
                                        process: ProcessHandle,
-                                       version: &Version) -> Result<usize, Error> {
-    // different versions have different layouts, check as appropiate
-    match version {
-        Version{major: 3, minor: 8, ..} => check_addresses::<v3_7_0::_is>(binary, maps, process),
-        Version{major: 3, minor: 7, ..} => check_addresses::<v3_7_0::_is>(binary, maps, process),
-        Version{major: 3, minor: 6, ..} => check_addresses::<v3_6_6::_is>(binary, maps, process),
-        Version{major: 3, minor: 5, ..} => check_addresses::<v3_5_5::_is>(binary, maps, process),
-        Version{major: 3, minor: 4, ..} => check_addresses::<v3_5_5::_is>(binary, maps, process),
-        Version{major: 3, minor: 3, ..} => check_addresses::<v3_3_7::_is>(binary, maps, process),
-        Version{major: 2, minor: 3...7, ..} => check_addresses::<v2_7_15::_is>(binary, maps, process),
-        _ => Err(format_err!("Unsupported version of Python: {}", version))
+                                       version: &Version,
+                                       pointer_width: PointerWidth) -> Result<usize, Error> {
+    let candidates = layout_candidates(version);
+    if candidates.is_empty() {
+        return Err(format_err!("Unsupported version of Python: {}", version));
+    }
+
+    // Try each candidate layout (closest known match first), and validate the result with
+    // the same consistency check `check_addresses` always ran, rather than committing to a
+    // blind version-to-layout mapping.
+    let mut attempted = Vec::new();
+    for layout in &candidates {
+        match (layout.scan)(binary, maps, process, pointer_width) {
+            Ok(addr) => return Ok(addr),
+            Err(_) => attempted.push(layout.name)
+        }
     }
+    Err(format_err!("Failed to find a python interpreter in the .data section (tried layout(s): {})",
+                     attempted.join(", ")))
+}
+
+// Parses just enough of a binary's own header to tell whether it's a 32-bit or 64-bit build,
+// so we can scan/interpret its memory with the target's word size rather than assuming it
+// matches the profiler's.
+fn pointer_width_of(filename: &str) -> Result<PointerWidth, Error> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut file = std::fs::File::open(filename)
+        .with_context(|_| format_err!("Failed to open {} to determine its pointer width", filename))?;
+
+    let mut magic = [0u8; 4];
+    file.read_exact(&mut magic)?;
+
+    // ELF: byte 4 (EI_CLASS) is 1 for 32-bit, 2 for 64-bit.
+    if &magic == b"\x7fELF" {
+        let mut ei_class = [0u8; 1];
+        file.read_exact(&mut ei_class)?;
+        return match ei_class[0] {
+            1 => Ok(PointerWidth::Bits32),
+            2 => Ok(PointerWidth::Bits64),
+            other => Err(format_err!("Unrecognized ELF class {} in {}", other, filename))
+        };
+    }
+
+    // Mach-O: no separate class byte, the magic number itself tells us the width.
+    match u32::from_le_bytes(magic) {
+        0xfeedface | 0xcefaedfe => return Ok(PointerWidth::Bits32),
+        0xfeedfacf | 0xcffaedfe => return Ok(PointerWidth::Bits64),
+        _ => {}
+    }
+
+    // PE: the "MZ" DOS header has a pointer at offset 0x3c to the COFF header, whose first
+    // field after the "PE\0\0" signature is a 2-byte Machine type.
+    if &magic[0..2] == b"MZ" {
+        file.seek(SeekFrom::Start(0x3c))?;
+        let mut e_lfanew = [0u8; 4];
+        file.read_exact(&mut e_lfanew)?;
+        file.seek(SeekFrom::Start(u32::from_le_bytes(e_lfanew) as u64 + 4))?;
+        let mut machine = [0u8; 2];
+        file.read_exact(&mut machine)?;
+        return match u16::from_le_bytes(machine) {
+            0x8664 | 0xaa64 => Ok(PointerWidth::Bits64), // x64 / arm64
+            0x14c => Ok(PointerWidth::Bits32), // x86
+            other => Err(format_err!("Unrecognized PE machine type {:#x} in {}", other, filename))
+        };
+    }
+
+    Err(format_err!("Unrecognized binary format for {}", filename))
+}
+
+// Reads a single target-width pointer out of a little-endian byte slice.
+fn read_pointer(bytes: &[u8], pointer_width: PointerWidth) -> usize {
+    let mut addr: usize = 0;
+    for i in 0..pointer_width.bytes() {
+        addr |= (bytes[i] as usize) << (i * 8);
+    }
+    addr
 }
 
 // Checks whether a block of memory (from BSS/.data etc) contains pointers that are pointing
 // to a valid PyInterpreterState
 fn check_addresses<I>(binary: &BinaryInfo,
                       maps: &[MapRange],
-                      process: ProcessHandle) -> Result<usize, Error>
+                      process: ProcessHandle,
+                      pointer_width: PointerWidth) -> Result<usize, Error>
         where I: python_interpreters::InterpreterState {
     // We're going to scan the BSS/data section for things, and try to narrowly scan things that
     // look like pointers to PyinterpreterState
     let bss = copy_address(binary.bss_addr as usize, binary.bss_size as usize, &process)?;
 
-    #[cfg_attr(feature = "cargo-clippy", allow(cast_ptr_alignment))]
-    let addrs = unsafe { slice::from_raw_parts(bss.as_ptr() as *const usize, bss.len() / size_of::<usize>()) };
+    // Step by the *target's* word size rather than our own. `PythonSpy::new` currently
+    // rejects any target whose width differs from ours before we get here, so today this is
+    // always our own word size too -- but keeping the scan itself width-aware means the only
+    // thing left to do, should cross-width support land later, is generating python_bindings
+    // for the other width.
+    let word_size = pointer_width.bytes();
+
+    for chunk in bss.chunks(word_size) {
+        if chunk.len() < word_size {
+            break;
+        }
+        let addr = read_pointer(chunk, pointer_width);
 
-    for &addr in addrs {
         // TODO: this doesn't seem to work on windows (pointer addresses outside of map ranges)
-        if maps_contain_addr(addr, maps) {
-            // this address points to valid memory. try loading it up as a PyInterpreterState
-            // to further check
-            let interp: I = copy_struct(addr, &process)?;
-
-            // get the pythreadstate pointer from the interpreter object, and if it is also
-            // a valid pointer then load it up.
-            let threads = interp.head();
-            if maps_contain_addr(threads as usize, maps) {
-                // If the threadstate points back to the interpreter like we expect, then
-                // this is almost certainly the address of the intrepreter
-                let thread = copy_pointer(threads, &process)?;
-
-                // as a final sanity check, try getting the stack_traces, and only return if this works
-                if thread.interp() as usize == addr && get_stack_traces(&interp, &process).is_ok() {
-                    return Ok(addr);
+        if maps_contain_addr(addr, maps) && verify_interpreter_address::<I>(addr, maps, process) {
+            return Ok(addr);
+        }
+    }
+    Err(format_err!("Failed to find a python interpreter in the .data section"))
+}
+
+// Loads up a candidate address as a PyInterpreterState and makes sure it's internally
+// consistent (its thread list points back to it) and that we can actually unwind stacks
+// from it, before trusting that it really is the interpreter. Used both when scanning
+// the BSS for candidate addresses and when validating an address found via symbols.
+fn verify_interpreter_address<I>(addr: usize, maps: &[MapRange], process: ProcessHandle) -> bool
+        where I: python_interpreters::InterpreterState {
+    let interp: I = match copy_struct(addr, &process) {
+        Ok(interp) => interp,
+        Err(_) => return false
+    };
+
+    // get the pythreadstate pointer from the interpreter object, and if it is also
+    // a valid pointer then load it up.
+    let threads = interp.head();
+    if !maps_contain_addr(threads as usize, maps) {
+        return false;
+    }
+    let thread = match copy_pointer(threads, &process) {
+        Ok(thread) => thread,
+        Err(_) => return false
+    };
+
+    // If the threadstate points back to the interpreter like we expect, then this is
+    // almost certainly the address of the interpreter, and we should be able to unwind it.
+    thread.interp() as usize == addr && get_stack_traces(&interp, &process).is_ok()
+}
+
+// Finds the main PyInterpreterState via _PyRuntime (3.7+, where the old interp_head global
+// was folded into the _PyRuntime struct).
+fn interp_addr_via_pyruntime(python_info: &PythonProcessInfo, process: ProcessHandle) -> Option<usize> {
+    // TODO: we actually want _PyRuntime.interpreters.head, and probably should
+    // generate bindings for the pyruntime object rather than hardcode the offset (24) here
+    python_info.get_symbol("_PyRuntime")
+        .and_then(|&addr| copy_struct((addr + 24) as usize, &process).ok())
+}
+
+// Finds the main PyInterpreterState via the pre-3.7 interp_head global.
+fn interp_addr_via_interp_head(python_info: &PythonProcessInfo, process: ProcessHandle) -> Option<usize> {
+    python_info.get_symbol("interp_head")
+        .and_then(|&addr| copy_struct(addr as usize, &process).ok())
+}
+
+/// A CPython ABI layout paired with the `InterpreterState`/`ThreadState` bindings that model
+/// it, used to pick (and then verify) the closest known-compatible layout for a detected
+/// `Version` instead of hardcoding a version-to-binding match everywhere that needs one.
+#[derive(Clone, Copy)]
+struct InterpreterLayout {
+    name: &'static str,
+    symbol_addr: fn(&PythonProcessInfo, ProcessHandle) -> Option<usize>,
+    scan: fn(&BinaryInfo, &[MapRange], ProcessHandle, PointerWidth) -> Result<usize, Error>,
+    verify: fn(usize, &[MapRange], ProcessHandle) -> bool,
+    stack_traces: fn(&PythonSpy) -> Result<Vec<StackTrace>, Error>
+}
+
+fn layout_for<I: InterpreterState>(name: &'static str,
+                                   symbol_addr: fn(&PythonProcessInfo, ProcessHandle) -> Option<usize>) -> InterpreterLayout {
+    InterpreterLayout{name, symbol_addr, scan: check_addresses::<I>, verify: verify_interpreter_address::<I>,
+                       stack_traces: PythonSpy::_get_stack_traces::<I>}
+}
+
+// Returns the known-compatible layout(s) to try for a given version, closest match first.
+// Ordinarily there's exactly one candidate (an exact minor-version match); for a minor
+// version newer than anything we know about we fall back to the newest known layout(s) as a
+// best effort, letting the caller verify the choice before committing to it. This way a new
+// CPython release that happens to share its predecessor's ABI (as 3.8 did with 3.7) keeps
+// working without a code change, instead of erroring out on an unrecognized version.
+fn layout_candidates(version: &Version) -> Vec<InterpreterLayout> {
+    // (major, minor range lo, minor range hi, layout), newest first
+    let known: [(u64, u64, u64, InterpreterLayout); 5] = [
+        (3, 7, 8, layout_for::<v3_7_0::_is>("3.7-3.8 (v3_7_0 bindings)", interp_addr_via_pyruntime)),
+        (3, 6, 6, layout_for::<v3_6_6::_is>("3.6 (v3_6_6 bindings)", interp_addr_via_interp_head)),
+        (3, 4, 5, layout_for::<v3_5_5::_is>("3.4-3.5 (v3_5_5 bindings)", interp_addr_via_interp_head)),
+        (3, 3, 3, layout_for::<v3_3_7::_is>("3.3 (v3_3_7 bindings)", interp_addr_via_interp_head)),
+        (2, 3, 7, layout_for::<v2_7_15::_is>("2.3-2.7 (v2_7_15 bindings)", interp_addr_via_interp_head)),
+    ];
+
+    if let Some(&(_, _, _, layout)) = known.iter()
+            .find(|&&(major, lo, hi, _)| version.major == major && version.minor >= lo && version.minor <= hi) {
+        return vec![layout];
+    }
+
+    // Likely a CPython release newer than any we've explicitly verified: best-effort try
+    // the newest known layout(s) rather than giving up immediately.
+    if version.major == 3 && version.minor > 8 {
+        return known.iter().take(2).map(|&(_, _, _, layout)| layout).collect();
+    }
+
+    Vec::new()
+}
+
+// Symbols that identify a binary as (or as embedding) a CPython interpreter, for recognizing
+// renamed/statically-linked/embedded Python where the mapped filename doesn't help.
+const PYTHON_IDENTIFYING_SYMBOLS: [&str; 4] = ["Py_GetVersion", "Py_Initialize", "_PyRuntime", "interp_head"];
+
+// Looks for the Python interpreter among every executable mapping, identifying it by its
+// exported symbols (or, failing that, a sys.version-shaped string in its BSS) rather than
+// by filename. Used as a fallback when the "bin/python"/"python.exe" name heuristic misses,
+// e.g. musl/Alpine installs, statically linked interpreters, or embedded hosts like uwsgi.
+//
+// This is done in two full passes rather than interleaved per-map, since the BSS scan is a
+// much weaker signal (a loose version-shaped regex match) than the symbol check: an ordinary
+// shared library (openssl, sqlite, zlib, ...) mapped before the real interpreter could
+// otherwise be misidentified as "the python binary" just because it happens to come first.
+fn find_embedded_python_binary(maps: &[MapRange], process: ProcessHandle) -> Option<(BinaryInfo, String, u64)> {
+    let mut parsed = Vec::new();
+    for map in maps.iter().filter(|m| m.is_exec()) {
+        let filename = match map.filename() {
+            Some(filename) => filename.clone(),
+            None => continue
+        };
+
+        let binary = match parse_binary(&filename, map.start() as u64) {
+            Ok(binary) => binary,
+            Err(_) => continue
+        };
+
+        if PYTHON_IDENTIFYING_SYMBOLS.iter().any(|symbol| binary.symbols.contains_key(*symbol)) {
+            return Some((binary, filename, map.start() as u64));
+        }
+
+        parsed.push((binary, filename, map.start() as u64));
+    }
+
+    // No mapping exported a recognizable Python symbol: fall back to the weaker BSS scan,
+    // now that we know no stronger match exists anywhere in the process.
+    for (binary, filename, start) in parsed {
+        if binary.bss_addr != 0 {
+            if let Ok(bss) = copy_address(binary.bss_addr as usize, binary.bss_size as usize, &process) {
+                if Version::scan_bytes(&bss).is_ok() {
+                    return Some((binary, filename, start));
                 }
             }
         }
     }
-    Err(format_err!("Failed to find a python interpreter in the .data section"))
+    None
 }
 
 /// Holds information about the python process: memory map layout, parsed binary info
@@ -290,7 +618,7 @@ pub struct PythonProcessInfo {
 }
 
 impl PythonProcessInfo {
-    fn new(pid: u32) -> Result<PythonProcessInfo, Error> {
+    fn new(pid: u32, process: ProcessHandle) -> Result<PythonProcessInfo, Error> {
         // get virtual memory layout
         let maps = get_process_maps(pid as pid_t)?;
 
@@ -307,21 +635,33 @@ impl PythonProcessInfo {
                     pathname.contains(python_bin_pattern) && m.is_exec()
                 } else {
                     false
-                }).ok_or_else(|| format_err!("Couldn't find python binary"))?;
+                });
 
-            let filename = map.filename().clone().unwrap();
-            // TODO: consistent types? u64 -> usize? for map.start etc
-            let mut python_binary = parse_binary(&filename, map.start() as u64)?;
+            // base_addr is only consulted by the windows/macos symbol-adjustment blocks below
+            #[cfg_attr(not(any(windows, target_os = "macos")), allow(unused_variables))]
+            let (mut python_binary, filename, base_addr) = match map {
+                Some(map) => {
+                    let filename = map.filename().clone().unwrap();
+                    // TODO: consistent types? u64 -> usize? for map.start etc
+                    (parse_binary(&filename, map.start() as u64)?, filename, map.start() as u64)
+                },
+                // Alpine/musl installs, statically linked interpreters, and embedded hosts
+                // (uwsgi, gunicorn workers, renamed executables) don't have "bin/python" in
+                // their mapped path. Fall back to probing every executable mapping and
+                // recognizing Python by its exports/BSS contents instead of its name.
+                None => find_embedded_python_binary(&maps, process)
+                    .ok_or_else(|| format_err!("Couldn't find python binary"))?
+            };
 
             // windows symbols are stored in separate files (.pdb), load
             #[cfg(windows)]
-            python_binary.symbols.extend(get_windows_python_symbols(pid, &filename, map.start() as u64)?);
+            python_binary.symbols.extend(get_windows_python_symbols(pid, &filename, base_addr)?);
 
             // For OSX, need to adjust main binary symbols by substracting _mh_execute_header
             // (which we've added to by map.start already, so undo that here)
             #[cfg(target_os = "macos")]
             {
-                let offset = python_binary.symbols["_mh_execute_header"] - map.start() as u64;
+                let offset = python_binary.symbols["_mh_execute_header"] - base_addr;
                 for address in python_binary.symbols.values_mut() {
                     *address -= offset;
                 }
@@ -335,8 +675,11 @@ impl PythonProcessInfo {
 
         // likewise handle libpython for python versions compiled with --enabled-shared
         let libpython_binary = {
+            // musl (Alpine) installs nest libpython under a config-specific directory, e.g.
+            // ".../config-3.9-x86_64-linux-musl/libpython3.9.so", so match on the filename
+            // alone rather than requiring a "lib/" prefix right before it.
             #[cfg(unix)]
-            let is_python_lib = |pathname: &str| pathname.contains("lib/libpython");
+            let is_python_lib = |pathname: &str| pathname.contains("libpython");
 
             #[cfg(windows)]
             let is_python_lib = |pathname: &str| pathname.contains("\\python") && pathname.ends_with("dll");
@@ -373,6 +716,30 @@ impl PythonProcessInfo {
             None => None
         }
     }
+
+    // PyPy's rpython runtime doesn't export _PyRuntime/interp_head, but it does export a
+    // bunch of pypy_g_*/rpython_* symbols generated by the rpython translation toolchain.
+    fn has_symbol_prefix(&self, prefix: &str) -> bool {
+        self.python_binary.symbols.keys().any(|symbol| symbol.starts_with(prefix)) ||
+            self.libpython_binary.as_ref().map_or(false, |binary| {
+                binary.symbols.keys().any(|symbol| symbol.starts_with(prefix))
+            })
+    }
+
+    /// Guesses the interpreter implementation from its exported symbols. This is more
+    /// reliable than the `sys.version` banner scan, since it doesn't depend on being able
+    /// to locate that string in memory.
+    pub fn likely_implementation(&self) -> InterpreterImplementation {
+        if self.get_symbol("_PyRuntime").is_some() || self.get_symbol("interp_head").is_some() {
+            InterpreterImplementation::CPython
+        } else if self.has_symbol_prefix("pypy_g_") || self.has_symbol_prefix("rpython_") {
+            InterpreterImplementation::PyPy
+        } else if self.has_symbol_prefix("slp_") {
+            InterpreterImplementation::Stackless
+        } else {
+            InterpreterImplementation::CPython
+        }
+    }
 }
 
 // We can't use goblin to parse external symbol files (like in a separate .pdb file) on windows,
@@ -401,17 +768,50 @@ pub fn get_windows_python_symbols(pid: u32, filename: &str, base_addr: u64) -> s
     Ok(ret)
 }
 
+/// Identifies which Python runtime we're looking at. The struct layouts used
+/// by `python_interpreters`/`python_bindings` differ between implementations,
+/// so this needs to be known before we can pick an `InterpreterState` binding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterpreterImplementation {
+    CPython,
+    PyPy,
+    Stackless
+}
+
+impl std::fmt::Display for InterpreterImplementation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            InterpreterImplementation::CPython => write!(f, "CPython"),
+            InterpreterImplementation::PyPy => write!(f, "PyPy"),
+            InterpreterImplementation::Stackless => write!(f, "Stackless")
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct Version {
     pub major: u64,
     pub minor: u64,
     pub patch: u64,
-    pub release_flags: String
+    pub release_flags: String,
+    pub implementation: InterpreterImplementation
 }
 
 impl Version {
     pub fn scan_bytes(data: &[u8]) -> Result<Version, Error> {
         use regex::bytes::Regex;
+
+        // PyPy embeds a banner like "PyPy 7.1.1 ... [compatible Python 3.6.1]" rather
+        // than the CPython "3.6.1 (default, ...)" banner, so check for it first.
+        let pypy_re = Regex::new(r"PyPy (\d+)\.(\d+)\.(\d+)").unwrap();
+        if let Some(cap) = pypy_re.captures_iter(data).next() {
+            let major = std::str::from_utf8(&cap[1])?.parse::<u64>()?;
+            let minor = std::str::from_utf8(&cap[2])?.parse::<u64>()?;
+            let patch = std::str::from_utf8(&cap[3])?.parse::<u64>()?;
+            return Ok(Version{major, minor, patch, release_flags: "".to_owned(),
+                               implementation: InterpreterImplementation::PyPy});
+        }
+
         let re = Regex::new(r"(?:\D|^)((\d)\.(\d)\.(\d{1,2}))((a|b|c|rc)\d{1,2})? (.{1,64})").unwrap();
 
         if let Some(cap) = re.captures_iter(data).next() {
@@ -422,7 +822,8 @@ impl Version {
             let major = std::str::from_utf8(&cap[2])?.parse::<u64>()?;
             let minor = std::str::from_utf8(&cap[3])?.parse::<u64>()?;
             let patch = std::str::from_utf8(&cap[4])?.parse::<u64>()?;
-            return Ok(Version{major, minor, patch, release_flags:release.to_owned()});
+            return Ok(Version{major, minor, patch, release_flags:release.to_owned(),
+                               implementation: InterpreterImplementation::CPython});
         }
         Err(format_err!("failed to find version string"))
     }
@@ -442,13 +843,16 @@ mod tests {
     #[test]
     fn test_find_version() {
         let version = Version::scan_bytes(b"2.7.10 (default, Oct  6 2017, 22:29:07)").unwrap();
-        assert_eq!(version, Version{major: 2, minor: 7, patch: 10, release_flags: "".to_owned()});
+        assert_eq!(version, Version{major: 2, minor: 7, patch: 10, release_flags: "".to_owned(),
+                                     implementation: InterpreterImplementation::CPython});
 
         let version = Version::scan_bytes(b"3.6.3 |Anaconda custom (64-bit)| (default, Oct  6 2017, 12:04:38)").unwrap();
-        assert_eq!(version, Version{major: 3, minor: 6, patch: 3, release_flags: "".to_owned()});
+        assert_eq!(version, Version{major: 3, minor: 6, patch: 3, release_flags: "".to_owned(),
+                                     implementation: InterpreterImplementation::CPython});
 
         let version = Version::scan_bytes(b"Python 3.7.0rc1 (v3.7.0rc1:dfad352267, Jul 20 2018, 13:27:54)").unwrap();
-        assert_eq!(version, Version{major: 3, minor: 7, patch: 0, release_flags: "rc1".to_owned()});
+        assert_eq!(version, Version{major: 3, minor: 7, patch: 0, release_flags: "rc1".to_owned(),
+                                     implementation: InterpreterImplementation::CPython});
 
         let version = Version::scan_bytes(b"53.7.0rc1 (v53.7.0rc1:dfad352267, Jul 20 2018, 13:27:54)");
         assert!(version.is_err(), "Shouldn't allow v53 of python (yet)");
@@ -459,4 +863,11 @@ mod tests {
         let version = Version::scan_bytes(b"3.7.10fooboo ");
         assert!(version.is_err(), "limit suffixes");
     }
+
+    #[test]
+    fn test_find_pypy_version() {
+        let version = Version::scan_bytes(b"PyPy 7.1.1 with GCC 7.3.1 [PyPy 7.1.1-beta0, compatible Python 3.6.1]").unwrap();
+        assert_eq!(version, Version{major: 7, minor: 1, patch: 1, release_flags: "".to_owned(),
+                                     implementation: InterpreterImplementation::PyPy});
+    }
 }
\ No newline at end of file